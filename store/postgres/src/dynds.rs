@@ -1,9 +1,15 @@
 //! SQL queries to load dynamic data sources
 
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::ops::Bound;
+use std::sync::{Arc, Mutex};
 
+use diesel::insert_into;
 use diesel::pg::PgConnection;
-use diesel::prelude::{ExpressionMethods, JoinOnDsl, QueryDsl, RunQueryDsl};
+use diesel::prelude::{ExpressionMethods, QueryDsl, RunQueryDsl};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
 
 use graph::{
     components::store::StoredDynamicDataSource,
@@ -22,18 +28,21 @@ use crate::block_range::first_block_in_range;
 //      -g diesel store/postgres/src/subgraphs.graphql subgraphs
 // BEGIN GENERATED CODE
 table! {
-    subgraphs.dynamic_ethereum_contract_data_source (vid) {
+    subgraphs.dynamic_data_source (vid) {
         vid -> BigInt,
         id -> Text,
         kind -> Text,
         name -> Text,
         network -> Nullable<Text>,
-        source -> Text,
+        // Opaque, chain-defined bytes identifying how this data source was
+        // created. Only the codec for the chain that produced them knows
+        // how to turn them back into that chain's notion of a source; see
+        // `DynDataSourceCodec`.
+        param -> Nullable<Binary>,
         mapping -> Text,
-        ethereum_block_hash -> Binary,
-        ethereum_block_number -> Numeric,
-        deployment -> Text,
         context -> Nullable<Text>,
+        creation_block -> Integer,
+        deployment -> Text,
         block_range -> Range<Integer>,
     }
 }
@@ -43,18 +52,178 @@ table! {
         vid -> BigInt,
         id -> Text,
         address -> Nullable<Binary>,
-        abi -> Text,
+        // References `contract_abi.hash`; the ABI text itself is no longer
+        // stored inline so that template instances sharing an ABI don't
+        // each pay for a copy of it.
+        abi_hash -> Text,
         start_block -> Nullable<Numeric>,
         block_range -> Range<Integer>,
     }
 }
 
+table! {
+    subgraphs.contract_abi (hash) {
+        hash -> Text,
+        abi -> Text,
+    }
+}
+
 // END GENERATED CODE
 
-allow_tables_to_appear_in_same_query!(
-    dynamic_ethereum_contract_data_source,
-    ethereum_contract_source
-);
+/// Hash of an ABI's canonicalized text, used as the primary key of
+/// `contract_abi` so that identical ABIs are only ever stored once.
+fn abi_hash(abi: &str) -> String {
+    hex::encode(Sha256::digest(canonicalize_abi(abi).as_bytes()))
+}
+
+/// Re-serialize `abi` with object keys sorted and insignificant whitespace
+/// removed, so ABIs that are byte-different but semantically identical
+/// (e.g. from different codegen paths) still hash to the same value.
+/// Falls back to the raw text if it isn't valid JSON.
+fn canonicalize_abi(abi: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(abi)
+        .and_then(|value| serde_json::to_string(&value))
+        .unwrap_or_else(|_| abi.to_string())
+}
+
+/// Store `abi` in `contract_abi` if it isn't there already, and return its
+/// hash.
+pub fn ensure_abi(conn: &PgConnection, abi: &str) -> Result<String, StoreError> {
+    use contract_abi as ca;
+
+    let hash = abi_hash(abi);
+    insert_into(ca::table)
+        .values((ca::hash.eq(&hash), ca::abi.eq(abi)))
+        .on_conflict(ca::hash)
+        .do_nothing()
+        .execute(conn)?;
+    Ok(hash)
+}
+
+/// Look up the ABI text stored under each of `hashes` in one query.
+fn resolve_abis(
+    conn: &PgConnection,
+    hashes: &[&str],
+) -> Result<HashMap<String, String>, StoreError> {
+    use contract_abi as ca;
+
+    Ok(ca::table
+        .filter(ca::hash.eq_any(hashes))
+        .select((ca::hash, ca::abi))
+        .load::<(String, String)>(conn)?
+        .into_iter()
+        .collect())
+}
+
+/// Decodes the chain-specific `param` blob of a dynamic data source back
+/// into whatever representation that chain's data sources need at runtime.
+/// `load` only knows how to fetch and order rows; each chain owns the
+/// meaning of the bytes it stored as `param`.
+pub trait DynDataSourceCodec {
+    /// The chain's own notion of a decoded dynamic data source source, e.g.
+    /// `Source` (address/abi/start_block) for Ethereum.
+    type DataSourceParam;
+
+    /// Decode `params`, the `(id, param)` of every row `load` fetched for
+    /// `deployment`, in the same order. Implementations must resolve their
+    /// own chain-specific tables (e.g. `ethereum_contract_source`) in a
+    /// bounded number of queries covering all of `params`, not one query
+    /// per entry. Returns an error if a `param` is missing or can't be
+    /// resolved, since a dynamic data source without a usable source is a
+    /// storage bug, not a valid state.
+    fn decode_params(
+        conn: &PgConnection,
+        deployment: &str,
+        params: Vec<(String, Option<Vec<u8>>)>,
+    ) -> Result<Vec<Self::DataSourceParam>, StoreError>;
+}
+
+/// The codec for Ethereum. Dynamic data sources on Ethereum store the id of
+/// their `ethereum_contract_source` row as `param` and resolve the actual
+/// address/ABI/start block from that table, then convert them with `to_source`.
+pub struct EthereumDataSourceCodec;
+
+impl DynDataSourceCodec for EthereumDataSourceCodec {
+    type DataSourceParam = Source;
+
+    fn decode_params(
+        conn: &PgConnection,
+        deployment: &str,
+        params: Vec<(String, Option<Vec<u8>>)>,
+    ) -> Result<Vec<Source>, StoreError> {
+        use ethereum_contract_source as ecs;
+
+        let source_ids = params
+            .into_iter()
+            .map(|(ds_id, param)| {
+                let source_id = param
+                    .map(|bytes| {
+                        String::from_utf8(bytes).map_err(|_| {
+                            constraint_violation!(
+                                "Dynamic data source {} for deployment {} has a param that is not a valid source id",
+                                ds_id,
+                                deployment
+                            )
+                        })
+                    })
+                    .transpose()?
+                    .ok_or_else(|| {
+                        constraint_violation!(
+                            "Dynamic data source {} for deployment {} is missing its param",
+                            ds_id,
+                            deployment
+                        )
+                    })?;
+                Ok((ds_id, source_id))
+            })
+            .collect::<Result<Vec<(String, String)>, StoreError>>()?;
+
+        let mut distinct_ids: Vec<&str> =
+            source_ids.iter().map(|(_, id)| id.as_str()).collect();
+        distinct_ids.sort_unstable();
+        distinct_ids.dedup();
+
+        let sources: HashMap<String, (Option<Vec<u8>>, String, Option<BigDecimal>)> = ecs::table
+            .filter(ecs::id.eq_any(&distinct_ids))
+            .select((ecs::id, ecs::address, ecs::abi_hash, ecs::start_block))
+            .load::<(String, Option<Vec<u8>>, String, Option<BigDecimal>)>(conn)?
+            .into_iter()
+            .map(|(id, address, abi_hash, start_block)| (id, (address, abi_hash, start_block)))
+            .collect();
+
+        let mut distinct_hashes: Vec<&str> =
+            sources.values().map(|(_, hash, _)| hash.as_str()).collect();
+        distinct_hashes.sort_unstable();
+        distinct_hashes.dedup();
+
+        let abis = resolve_abis(conn, &distinct_hashes)?;
+
+        source_ids
+            .into_iter()
+            .map(|(ds_id, source_id)| {
+                let (address, abi_hash, start_block) =
+                    sources.get(&source_id).cloned().ok_or_else(|| {
+                        constraint_violation!(
+                            "Dynamic data source {} for deployment {} references source {} which is not in ethereum_contract_source",
+                            ds_id,
+                            deployment,
+                            source_id
+                        )
+                    })?;
+                let abi = abis.get(&abi_hash).cloned().ok_or_else(|| {
+                    constraint_violation!(
+                        "Dynamic data source {} for deployment {} references ABI hash {} which is not in contract_abi",
+                        ds_id,
+                        deployment,
+                        abi_hash
+                    )
+                })?;
+
+                to_source(deployment, &ds_id, (address, abi, start_block))
+            })
+            .collect()
+    }
+}
 
 fn to_source(
     deployment: &str,
@@ -103,39 +272,98 @@ fn to_source(
     })
 }
 
-pub fn load(conn: &PgConnection, id: &str) -> Result<Vec<StoredDynamicDataSource>, StoreError> {
-    use dynamic_ethereum_contract_data_source as decds;
-    use ethereum_contract_source as ecs;
+/// Count the dynamic data sources of deployment `id` without fetching or
+/// decoding any of their columns.
+pub fn count(conn: &PgConnection, id: &str) -> Result<usize, StoreError> {
+    use dynamic_data_source as dds;
+
+    let count = dds::table
+        .filter(dds::deployment.eq(id))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(count as usize)
+}
+
+/// Cheaply check whether deployment `id` has any dynamic data sources at
+/// all, without running the queries and decoding that `load` does.
+pub fn has_dynamic_data_sources(conn: &PgConnection, id: &str) -> Result<bool, StoreError> {
+    use diesel::dsl::exists;
+    use diesel::select;
+    use dynamic_data_source as dds;
+
+    Ok(select(exists(dds::table.filter(dds::deployment.eq(id)))).get_result(conn)?)
+}
+
+pub fn load<C: DynDataSourceCodec>(
+    conn: &PgConnection,
+    id: &str,
+) -> Result<Vec<StoredDynamicDataSource<C::DataSourceParam>>, StoreError> {
+    load_from::<C>(conn, id, None)
+}
+
+/// Like `load`, but only returns data sources created at or after
+/// `from_block`, honoring the `block_range` lower bound. Lets callers
+/// resume after a revert or a block range advance without re-reading and
+/// re-decoding the deployment's entire history on every call.
+pub fn load_since<C: DynDataSourceCodec>(
+    conn: &PgConnection,
+    id: &str,
+    from_block: i32,
+) -> Result<Vec<StoredDynamicDataSource<C::DataSourceParam>>, StoreError> {
+    load_from::<C>(conn, id, Some(from_block))
+}
+
+fn load_from<C: DynDataSourceCodec>(
+    conn: &PgConnection,
+    id: &str,
+    from_block: Option<i32>,
+) -> Result<Vec<StoredDynamicDataSource<C::DataSourceParam>>, StoreError> {
+    use dynamic_data_source as dds;
 
     // Query to load the data sources. Ordering by the creation block and `vid` makes sure they are
     // in insertion order which is important for the correctness of reverts and the execution order
     // of triggers. See also 8f1bca33-d3b7-4035-affc-fd6161a12448.
-    let dds: Vec<_> = decds::table
-        .inner_join(ecs::table.on(decds::source.eq(ecs::id)))
-        .filter(decds::deployment.eq(id))
+    let mut query = dds::table.filter(dds::deployment.eq(id)).into_boxed();
+    if let Some(from_block) = from_block {
+        // `creation_block` mirrors the lower bound of `block_range`, so
+        // filtering on it is equivalent to filtering on the range itself
+        // without needing a range-specific SQL operator.
+        query = query.filter(dds::creation_block.ge(from_block));
+    }
+
+    let rows: Vec<_> = query
         .select((
-            decds::id,
-            decds::name,
-            decds::context,
-            (ecs::address, ecs::abi, ecs::start_block),
-            decds::block_range,
+            dds::id,
+            dds::name,
+            dds::context,
+            dds::param,
+            dds::block_range,
         ))
-        .order_by((decds::ethereum_block_number, decds::vid))
+        .order_by((dds::creation_block, dds::vid))
         .load::<(
             String,
             String,
             Option<String>,
-            (Option<Vec<u8>>, String, Option<BigDecimal>),
+            Option<Vec<u8>>,
             (Bound<i32>, Bound<i32>),
         )>(conn)?;
 
-    let mut data_sources: Vec<StoredDynamicDataSource> = Vec::new();
-    for (ds_id, name, context, source, range) in dds.into_iter() {
-        let source = to_source(id, &ds_id, source)?;
+    // Resolve every row's param in one batch instead of once per row, so
+    // `load` stays a fixed number of queries no matter how many data
+    // sources `id` has.
+    let params = rows
+        .iter()
+        .map(|(ds_id, _, _, param, _)| (ds_id.clone(), param.clone()))
+        .collect();
+    let decoded = C::decode_params(conn, id, params)?;
+
+    let mut data_sources: Vec<StoredDynamicDataSource<C::DataSourceParam>> =
+        Vec::with_capacity(rows.len());
+    for ((_, name, context, _, range), param) in rows.into_iter().zip(decoded) {
         let creation_block = first_block_in_range(&range);
         let data_source = StoredDynamicDataSource {
             name,
-            source,
+            param,
             context,
             creation_block: creation_block.map(|n| n as u64),
         };
@@ -150,3 +378,100 @@ pub fn load(conn: &PgConnection, id: &str) -> Result<Vec<StoredDynamicDataSource
     }
     Ok(data_sources)
 }
+
+/// Caches the dynamic data sources of a bounded number of deployments in
+/// memory, so that an actively-indexing subgraph that reads them on every
+/// block doesn't have to repeat the queries and per-chain decoding that
+/// `load` does on every call.
+///
+/// `P` is the chain's decoded data source param type (e.g. `Source` for
+/// Ethereum). Ordering by creation block is load-bearing for revert and
+/// trigger correctness (see `load`), so cached entries always hold the
+/// exact vector `load` would have produced.
+///
+/// Freshness doesn't rely on every insert or revert remembering to call
+/// `evict`: each `load` call also cheaply checks `id`'s current
+/// `freshness_token` and refreshes the cached entry if it no longer
+/// matches, so a forgotten `evict` call costs one query rather than stale
+/// data.
+///
+/// Note: no caller in this tree has been switched from `load`/`load_since`
+/// to this cache yet, and nothing calls `evict`; this is the cache
+/// primitive the indexing read path is meant to adopt, not a wired-in
+/// replacement for `load`.
+pub struct DataSourceCache<P> {
+    cache: Mutex<LruCache<String, ((i64, Option<i64>), Arc<Vec<StoredDynamicDataSource<P>>>)>>,
+}
+
+impl<P> DataSourceCache<P> {
+    /// Create a cache that keeps the dynamic data sources of at most
+    /// `capacity` deployments in memory at once, evicting the
+    /// least-recently-used deployment once that's exceeded. A `capacity`
+    /// of 0 is treated as 1.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::new(1).unwrap());
+        Self {
+            cache: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Return the dynamic data sources for `id`, trying the in-memory copy
+    /// first and falling back to `load` (and therefore Postgres) whenever
+    /// there's no cached entry or `id`'s `freshness_token` has changed
+    /// since it was cached.
+    pub fn load<C: DynDataSourceCodec<DataSourceParam = P>>(
+        &self,
+        conn: &PgConnection,
+        id: &str,
+    ) -> Result<Arc<Vec<StoredDynamicDataSource<P>>>, StoreError> {
+        let current_token = freshness_token(conn, id)?;
+
+        if let Some((cached_token, data_sources)) = self.cache.lock().unwrap().get(id) {
+            if *cached_token == current_token {
+                return Ok(data_sources.clone());
+            }
+        }
+
+        let data_sources = Arc::new(load::<C>(conn, id)?);
+        self.cache
+            .lock()
+            .unwrap()
+            .put(id.to_string(), (current_token, data_sources.clone()));
+        Ok(data_sources)
+    }
+
+    /// Evict the cached entry for `id` outright, instead of waiting for the
+    /// next `load` to notice its `freshness_token` changed. The next `load`
+    /// for `id` then re-reads from Postgres even if the token is unchanged.
+    /// It still runs the `freshness_token` query first, so evicting saves
+    /// no query; inserts and deletions already change the token, so calling
+    /// this after them is not needed for correctness.
+    pub fn evict(&self, id: &str) {
+        self.cache.lock().unwrap().pop(id);
+    }
+}
+
+/// A cheap summary of deployment `id`'s dynamic
+/// data sources, used by `DataSourceCache` to detect a stale cached entry.
+/// Pairs the row count (catches deletions) with the maximum `vid` (catches
+/// insertions, including a delete-then-insert pair that leaves the count
+/// unchanged), since `vid` never decreases or repeats.
+fn freshness_token(conn: &PgConnection, id: &str) -> Result<(i64, Option<i64>), StoreError> {
+    use diesel::dsl::{count_star, max};
+    use dynamic_data_source as dds;
+
+    Ok(dds::table
+        .filter(dds::deployment.eq(id))
+        .select((count_star(), max(dds::vid)))
+        .first::<(i64, Option<i64>)>(conn)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::abi_hash;
+
+    #[test]
+    fn abi_hash_ignores_key_order() {
+        assert_eq!(abi_hash(r#"{"a":1,"b":2}"#), abi_hash(r#"{"b":2,"a":1}"#));
+    }
+}